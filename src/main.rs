@@ -1,6 +1,7 @@
 use anyhow::{anyhow, bail, Result};
 use clap::Parser;
 use indoc::indoc;
+use notify::{Event, RecursiveMode, Watcher};
 use serde::Deserialize;
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
@@ -11,6 +12,8 @@ use std::{
     fs::{File, Permissions},
     io::Write,
     path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
 };
 
 #[derive(Parser, Debug)]
@@ -27,6 +30,11 @@ struct Args {
     /// will be included in the output.
     #[arg(long, value_delimiter = ',')]
     services_enabled: Option<Vec<String>>,
+    /// Keep running after the initial generation, regenerating the output
+    /// whenever a service definition file (`.toml`, `.yaml`, `.yml`, or `.json`)
+    /// in `input_dir` is created, modified, or removed.
+    #[arg(long)]
+    watch: bool,
 }
 
 #[derive(Deserialize)]
@@ -66,6 +74,8 @@ impl Display for ServiceType {
 struct Extensions {
     log: Option<Log>,
     restart: Option<Restart>,
+    user: Option<RunAs>,
+    build: Option<Build>,
 }
 
 #[derive(Deserialize)]
@@ -80,39 +90,80 @@ struct Restart {
     on_failure: bool,
 }
 
+/// Account to drop privileges to via s6-setuidgid/s6-applyuidgid.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RunAs {
+    Username(String),
+    UidGid { uid: u32, gid: u32 },
+}
+
+impl RunAs {
+    fn wrapper_command(&self) -> String {
+        match self {
+            Self::Username(username) => format!("s6-setuidgid {username}"),
+            Self::UidGid { uid, gid } => format!("s6-applyuidgid -u {uid} -g {gid}"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Build {
+    command: String,
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
-    let paths = fs::read_dir(args.input_dir)?;
+    if args.watch {
+        watch(&args)?;
+    } else {
+        generate(&args)?;
+    }
+    Ok(())
+}
+
+/// Run the read/transitive-closure/write pipeline once. Returns the names of
+/// the services that were actually emitted (i.e. enabled and written out).
+fn generate(args: &Args) -> Result<Vec<String>> {
+    let paths = fs::read_dir(&args.input_dir)?;
     let mut services = Vec::new();
+    let mut service_file_names: HashMap<String, String> = HashMap::new();
     for path in paths {
         let path = path?;
         let meta = path.metadata()?;
         if meta.is_file() {
-            let name = path
+            let file_name = path
                 .file_name()
                 .into_string()
-                .map_err(|_| anyhow!("illegal file name"))?
-                .trim_end_matches(".toml")
-                .to_string();
-            let file = fs::read_to_string(path.path())?;
-            let service: Service = toml::from_str(&file)?;
+                .map_err(|_| anyhow!("illegal file name"))?;
+            let Some((name, service)) = parse_service_file(&file_name, &path.path())? else {
+                continue;
+            };
+            check_service_name_collision(&mut service_file_names, &name, &file_name)?;
             services.push((name, service, false));
         }
     }
-    let services_enabled = {
-        let service_map: HashMap<String, &Service> =
-            services.iter().map(|(name, service, _)| (name.clone(), service)).collect();
-        if let Some(enabled) = &args.services_enabled {
-            // check that every service asked for explicitly actually exists
-            for service in enabled.iter() {
-                if !service_map.contains_key(service) {
-                    bail!("service {} not found but was specified in --services-enabled", service);
-                }
+    let service_map: HashMap<String, &Service> =
+        services.iter().map(|(name, service, _)| (name.clone(), service)).collect();
+    if let Some(enabled) = &args.services_enabled {
+        // check that every service asked for explicitly actually exists
+        for service in enabled.iter() {
+            if !service_map.contains_key(service) {
+                bail!("service {} not found but was specified in --services-enabled", service);
             }
         }
-        transitive_closure(service_map, args.services_enabled.clone())
-    };
+    }
+    // Compute the enabled set first (a non-bailing traversal), then validate
+    // only that subgraph: a disabled service can never end up in `output_dir`,
+    // so a cycle or missing dependency confined to it shouldn't fail the run.
+    let services_enabled = transitive_closure(service_map.clone(), args.services_enabled.clone());
+    validate_dependencies(&service_map, &services_enabled)?;
     let mut service_logs: HashMap<String, PathBuf> = HashMap::new();
+    // names of derived services (e.g. `build` prerequisites) that are internal
+    // plumbing and should never appear directly in the user bundle
+    let mut internal_services: HashSet<String> = HashSet::new();
+    let mut emitted = Vec::new();
     let user_contents_dir = args.output_dir.join("user").join("contents.d");
     let _ = fs::remove_dir_all(&user_contents_dir);
     fs::create_dir_all(&user_contents_dir)?;
@@ -123,10 +174,39 @@ fn main() -> Result<()> {
                 println!("skipping {name} because it's not enabled");
                 continue;
             }
+            emitted.push(name.clone());
             let service_dir = args.output_dir.join(&name);
             fs::create_dir_all(&service_dir)?;
             // process extensions first, since they can mutate the service definition
+            // tracks whether `log` relocated a oneshot's real command from `up`
+            // into `run`, so that `user` below knows where to find it
+            let mut oneshot_run_holds_real_command = false;
             if let Some(ref ext) = service.extensions {
+                // `build` runs before `log`, since it prepends a prerequisite
+                // onto `service.dependencies` that the `log` block below
+                // clones onto the paired `<name>-log` longrun logger; doing
+                // it in this order keeps the producer and its logger
+                // depending on the same build step.
+                if let Some(ref build) = ext.build {
+                    let build_name = build_service_name(&name, &service_file_names)?;
+                    internal_services.insert(build_name.clone());
+                    more_services.push((
+                        build_name.clone(),
+                        Service {
+                            type_: ServiceType::OneShot,
+                            up: Some(build_up(&build.command)),
+                            run: None,
+                            finish: None,
+                            consumer_for: None,
+                            producer_for: None,
+                            pipeline_name: None,
+                            dependencies: None,
+                            extensions: None,
+                        },
+                        true,
+                    ));
+                    service.dependencies.get_or_insert_with(Vec::new).insert(0, build_name);
+                }
                 if let Some(ref log) = ext.log {
                     service_logs.insert(name.clone(), log.dir.clone().into());
                     match service.type_ {
@@ -138,6 +218,7 @@ fn main() -> Result<()> {
                                 &log.dir,
                                 &service_dir.canonicalize()?.join("run"),
                             ));
+                            oneshot_run_holds_real_command = true;
                         }
                         ServiceType::LongRun => {
                             let pipeline_name = format!("{name}-with-logs");
@@ -172,6 +253,34 @@ fn main() -> Result<()> {
                         service.finish = Some(no_restart_on_failure());
                     }
                 }
+                if let Some(ref run_as) = ext.user {
+                    let wrapper = run_as.wrapper_command();
+                    match service.type_ {
+                        ServiceType::OneShot if oneshot_run_holds_real_command => {
+                            // `log` already rewrote `up` into a wrapper that execs
+                            // `run`, so the real command to drop privileges on
+                            // lives in `run`, not `up`
+                            let Some(ref run) = service.run else {
+                                bail!(
+                                    "extension `user` requires `run` for oneshots combined with `log`"
+                                );
+                            };
+                            service.run = Some(wrap_exec(run, &wrapper)?);
+                        }
+                        ServiceType::OneShot => {
+                            let Some(ref up) = service.up else {
+                                bail!("extension `user` requires `up` for oneshots");
+                            };
+                            service.up = Some(wrap_exec(up, &wrapper)?);
+                        }
+                        ServiceType::LongRun => {
+                            let Some(ref run) = service.run else {
+                                bail!("extension `user` requires `run` for longruns");
+                            };
+                            service.run = Some(wrap_exec(run, &wrapper)?);
+                        }
+                    }
+                }
             }
             // write out service definition
             fs::write(service_dir.join("type"), service.type_.to_string())?;
@@ -204,8 +313,12 @@ fn main() -> Result<()> {
                 }
             }
             // only write this service to the user bundle if it's standalone,
-            // or the last service in a pipeline
-            if service.consumer_for.is_none() && service.producer_for.is_none() {
+            // or the last service in a pipeline; internal services (e.g. a
+            // `build` prerequisite) are never directly startable and are
+            // excluded regardless
+            if internal_services.contains(&name) {
+                // not bundled
+            } else if service.consumer_for.is_none() && service.producer_for.is_none() {
                 fs::write(user_contents_dir.join(name), "")?;
             } else if service.producer_for.is_none() {
                 if let Some(ref pipeline_name) = &service.pipeline_name {
@@ -220,14 +333,67 @@ fn main() -> Result<()> {
         }
         services = more_services;
     }
-    if let Some(output_logterm_config) = args.output_logterm_config {
+    if let Some(output_logterm_config) = &args.output_logterm_config {
         println!("writing logterm config to {}", output_logterm_config.display());
         fs::create_dir_all(output_logterm_config.parent().unwrap())?;
         fs::write(output_logterm_config, logterm_config(service_logs))?;
     }
+    Ok(emitted)
+}
+
+/// How long to wait after the last filesystem event before triggering a rebuild.
+/// Coalesces bursts from editors writing temp files or saving multiple files at once.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(150);
+
+/// Regenerate the s6 output once, then keep watching `input_dir` for changes to
+/// service definition files and regenerate again on each debounced batch of events.
+fn watch(args: &Args) -> Result<()> {
+    match generate(args) {
+        Ok(emitted) => print_emitted_summary(&emitted),
+        Err(err) => eprintln!("error during initial generation: {err:#}"),
+    }
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&args.input_dir, RecursiveMode::NonRecursive)?;
+    println!("watching {} for changes...", args.input_dir.display());
+    while let Ok(event) = rx.recv() {
+        if !is_service_file_change(&event) {
+            continue;
+        }
+        // drain any further events that arrive within the debounce window so a
+        // burst of saves collapses into a single rebuild
+        while rx.recv_timeout(WATCH_DEBOUNCE).is_ok() {}
+        println!("change detected, regenerating...");
+        match generate(args) {
+            Ok(emitted) => print_emitted_summary(&emitted),
+            Err(err) => eprintln!("error regenerating: {err:#}"),
+        }
+    }
     Ok(())
 }
 
+/// Print which services were (re-)emitted by a `generate` run.
+fn print_emitted_summary(emitted: &[String]) {
+    if emitted.is_empty() {
+        println!("re-emitted 0 services");
+    } else {
+        println!("re-emitted {} services: {}", emitted.len(), emitted.join(", "));
+    }
+}
+
+fn is_service_file_change(event: &notify::Result<Event>) -> bool {
+    match event {
+        Ok(event) => event.paths.iter().any(|p| {
+            p.extension()
+                .is_some_and(|ext| matches!(ext.to_str(), Some("toml" | "yaml" | "yml" | "json")))
+        }),
+        Err(err) => {
+            eprintln!("watch error: {err:#}");
+            false
+        }
+    }
+}
+
 fn log_run(path: &Path) -> String {
     format!(
         indoc! {r#"
@@ -252,6 +418,27 @@ fn log_up(path: &Path, run_script: &Path) -> String {
     )
 }
 
+fn build_up(command: &str) -> String {
+    format!(
+        indoc! {r#"
+            #!/bin/sh
+            exec {}
+        "#},
+        command
+    )
+}
+
+/// Derive the name of the oneshot prerequisite service synthesized for a
+/// `build` extension, bailing if it would clobber a service the user already
+/// defined on disk (where `existing` maps service name to its source file name).
+fn build_service_name(name: &str, existing: &HashMap<String, String>) -> Result<String> {
+    let build_name = format!("{name}-build");
+    if existing.contains_key(&build_name) {
+        bail!("extension `build` on {name} would clobber existing service {build_name}");
+    }
+    Ok(build_name)
+}
+
 fn no_restart_on_failure() -> String {
     format!(indoc! {r#"
         #!/bin/sh
@@ -259,6 +446,120 @@ fn no_restart_on_failure() -> String {
     "#})
 }
 
+/// Rewrite a script's trailing `exec <command>` line so the command runs under
+/// `wrapper` (e.g. `s6-setuidgid user`), dropping privileges before the real
+/// command starts. Bails if the script doesn't have an `exec` line to wrap.
+fn wrap_exec(script: &str, wrapper: &str) -> Result<String> {
+    let mut lines: Vec<String> = script.lines().map(|l| l.to_string()).collect();
+    let Some(idx) = lines.iter().rposition(|l| l.trim_start().starts_with("exec ")) else {
+        bail!("can't wrap script for extension `user`: no `exec` line found");
+    };
+    let command = lines[idx].trim_start().strip_prefix("exec ").unwrap().to_string();
+    lines[idx] = format!("exec {wrapper} {command}");
+    Ok(format!("{}\n", lines.join("\n")))
+}
+
+/// Parse a service definition file, dispatching on its extension. Returns `None`
+/// for files whose extension isn't one of the recognized service formats, so
+/// that stray files in `input_dir` are silently skipped rather than erroring.
+fn parse_service_file(file_name: &str, path: &Path) -> Result<Option<(String, Service)>> {
+    if ![".toml", ".yaml", ".yml", ".json"].iter().any(|ext| file_name.ends_with(ext)) {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(path)?;
+    parse_service_contents(file_name, &contents)
+}
+
+/// Derive a service's name and deserialize its definition from `contents`,
+/// dispatching on `file_name`'s extension (`.toml`, `.yaml`/`.yml`, `.json`).
+/// Returns `None` for files whose extension isn't one of the recognized
+/// service formats, so that stray files in `input_dir` are silently skipped
+/// rather than erroring.
+fn parse_service_contents(file_name: &str, contents: &str) -> Result<Option<(String, Service)>> {
+    let (stem, parse): (&str, fn(&str) -> Result<Service>) = if let Some(stem) =
+        file_name.strip_suffix(".toml")
+    {
+        (stem, |s| Ok(toml::from_str(s)?))
+    } else if let Some(stem) = file_name.strip_suffix(".yaml") {
+        (stem, |s| Ok(serde_yaml::from_str(s)?))
+    } else if let Some(stem) = file_name.strip_suffix(".yml") {
+        (stem, |s| Ok(serde_yaml::from_str(s)?))
+    } else if let Some(stem) = file_name.strip_suffix(".json") {
+        (stem, |s| Ok(serde_json::from_str(s)?))
+    } else {
+        return Ok(None);
+    };
+    Ok(Some((stem.to_string(), parse(contents)?)))
+}
+
+/// Record that `file_name` maps to service `name`, bailing if another file
+/// already claimed the same name.
+fn check_service_name_collision(
+    service_file_names: &mut HashMap<String, String>,
+    name: &str,
+    file_name: &str,
+) -> Result<()> {
+    if let Some(other) = service_file_names.insert(name.to_string(), file_name.to_string()) {
+        bail!("both {other} and {file_name} map to service name {name}");
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DfsColor {
+    White,
+    Gray,
+    Black,
+}
+
+/// Verify that every dependency of a service in `start` (e.g. the
+/// `--services-enabled` transitive closure) names an existing service and
+/// that the dependency graph reachable from `start` is acyclic, before
+/// anything is written to `output_dir`. Services outside `start` are never
+/// emitted, so problems confined to them are not validated here.
+fn validate_dependencies(services: &HashMap<String, &Service>, start: &[String]) -> Result<()> {
+    let mut colors: HashMap<String, DfsColor> =
+        services.keys().map(|name| (name.clone(), DfsColor::White)).collect();
+    let mut stack: Vec<String> = Vec::new();
+    for name in start {
+        visit_dependency(name, services, &mut colors, &mut stack)?;
+    }
+    Ok(())
+}
+
+fn visit_dependency(
+    name: &str,
+    services: &HashMap<String, &Service>,
+    colors: &mut HashMap<String, DfsColor>,
+    stack: &mut Vec<String>,
+) -> Result<()> {
+    match colors.get(name).copied() {
+        Some(DfsColor::Black) => return Ok(()),
+        Some(DfsColor::Gray) => {
+            let start = stack.iter().position(|n| n == name).unwrap();
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(name.to_string());
+            bail!("dependency cycle detected: {}", cycle.join(" -> "));
+        }
+        _ => {}
+    }
+    colors.insert(name.to_string(), DfsColor::Gray);
+    stack.push(name.to_string());
+    if let Some(service) = services.get(name) {
+        if let Some(deps) = &service.dependencies {
+            for dep in deps {
+                if !services.contains_key(dep) {
+                    bail!("service {name} depends on {dep}, which does not exist");
+                }
+                visit_dependency(dep, services, colors, stack)?;
+            }
+        }
+    }
+    stack.pop();
+    colors.insert(name.to_string(), DfsColor::Black);
+    Ok(())
+}
+
 fn transitive_closure(
     services: HashMap<String, &Service>,
     enabled: Option<Vec<String>>,
@@ -291,3 +592,159 @@ fn logterm_config(mut service_logs: HashMap<String, PathBuf>) -> String {
     res.push_str("\n");
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_exec_wraps_trailing_exec_line() {
+        let script = "#!/bin/sh\nexec mydaemon --flag\n";
+        let wrapped = wrap_exec(script, "s6-setuidgid appuser").unwrap();
+        assert_eq!(wrapped, "#!/bin/sh\nexec s6-setuidgid appuser mydaemon --flag\n");
+    }
+
+    #[test]
+    fn wrap_exec_bails_without_exec_line() {
+        let script = "#!/bin/sh\necho hello\n";
+        assert!(wrap_exec(script, "s6-setuidgid appuser").is_err());
+    }
+
+    #[test]
+    fn build_up_execs_the_given_command() {
+        let script = build_up("npm install");
+        assert_eq!(script, "#!/bin/sh\nexec npm install\n");
+    }
+
+    #[test]
+    fn is_service_file_change_true_for_recognized_extensions() {
+        for ext in ["toml", "yaml", "yml", "json"] {
+            let event = Ok(Event::new(notify::EventKind::Any)
+                .add_path(PathBuf::from(format!("web.{ext}"))));
+            assert!(is_service_file_change(&event));
+        }
+    }
+
+    #[test]
+    fn is_service_file_change_false_for_unrecognized_extension() {
+        let event = Ok(Event::new(notify::EventKind::Any).add_path(PathBuf::from("README.md")));
+        assert!(!is_service_file_change(&event));
+    }
+
+    #[test]
+    fn is_service_file_change_false_without_paths() {
+        let event = Ok(Event::new(notify::EventKind::Any));
+        assert!(!is_service_file_change(&event));
+    }
+
+    #[test]
+    fn is_service_file_change_false_on_watch_error() {
+        let event = Err(notify::Error::generic("boom"));
+        assert!(!is_service_file_change(&event));
+    }
+
+    #[test]
+    fn build_service_name_derives_from_service_name() {
+        let existing = HashMap::new();
+        assert_eq!(build_service_name("web", &existing).unwrap(), "web-build");
+    }
+
+    #[test]
+    fn build_service_name_bails_on_clobber() {
+        let mut existing = HashMap::new();
+        existing.insert("web-build".to_string(), "web-build.toml".to_string());
+        let err = build_service_name("web", &existing).unwrap_err();
+        assert!(err.to_string().contains("clobber"));
+    }
+
+    #[test]
+    fn parse_service_contents_parses_toml() {
+        let (name, service) =
+            parse_service_contents("web.toml", "type = \"oneshot\"\n").unwrap().unwrap();
+        assert_eq!(name, "web");
+        assert!(matches!(service.type_, ServiceType::OneShot));
+    }
+
+    #[test]
+    fn parse_service_contents_parses_yaml() {
+        let (name, service) =
+            parse_service_contents("web.yaml", "type: oneshot\n").unwrap().unwrap();
+        assert_eq!(name, "web");
+        assert!(matches!(service.type_, ServiceType::OneShot));
+    }
+
+    #[test]
+    fn parse_service_contents_parses_json() {
+        let (name, service) =
+            parse_service_contents("web.json", r#"{"type": "oneshot"}"#).unwrap().unwrap();
+        assert_eq!(name, "web");
+        assert!(matches!(service.type_, ServiceType::OneShot));
+    }
+
+    #[test]
+    fn parse_service_contents_ignores_unrecognized_extension() {
+        assert!(parse_service_contents("README.md", "").unwrap().is_none());
+    }
+
+    #[test]
+    fn check_service_name_collision_bails_on_duplicate() {
+        let mut service_file_names = HashMap::new();
+        check_service_name_collision(&mut service_file_names, "web", "web.toml").unwrap();
+        let err =
+            check_service_name_collision(&mut service_file_names, "web", "web.yaml").unwrap_err();
+        assert!(err.to_string().contains("both web.toml and web.yaml map to service name web"));
+    }
+
+    fn mk_service(dependencies: Option<Vec<&str>>) -> Service {
+        Service {
+            type_: ServiceType::OneShot,
+            up: Some("#!/bin/sh\nexec true\n".to_string()),
+            run: None,
+            finish: None,
+            consumer_for: None,
+            producer_for: None,
+            pipeline_name: None,
+            dependencies: dependencies.map(|deps| deps.into_iter().map(String::from).collect()),
+            extensions: None,
+        }
+    }
+
+    #[test]
+    fn validate_dependencies_detects_missing_dependency() {
+        let a = mk_service(Some(vec!["b"]));
+        let services: HashMap<String, &Service> = [("a".to_string(), &a)].into_iter().collect();
+        let err = validate_dependencies(&services, &["a".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("does not exist"));
+    }
+
+    #[test]
+    fn validate_dependencies_detects_cycle() {
+        let a = mk_service(Some(vec!["b"]));
+        let b = mk_service(Some(vec!["a"]));
+        let services: HashMap<String, &Service> =
+            [("a".to_string(), &a), ("b".to_string(), &b)].into_iter().collect();
+        let err = validate_dependencies(&services, &["a".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("cycle"));
+    }
+
+    #[test]
+    fn validate_dependencies_allows_acyclic_graph() {
+        let a = mk_service(Some(vec!["b"]));
+        let b = mk_service(None);
+        let services: HashMap<String, &Service> =
+            [("a".to_string(), &a), ("b".to_string(), &b)].into_iter().collect();
+        assert!(validate_dependencies(&services, &["a".to_string()]).is_ok());
+    }
+
+    #[test]
+    fn validate_dependencies_ignores_cycle_outside_enabled_set() {
+        let a = mk_service(None);
+        let b = mk_service(Some(vec!["c"]));
+        let c = mk_service(Some(vec!["b"]));
+        let services: HashMap<String, &Service> =
+            [("a".to_string(), &a), ("b".to_string(), &b), ("c".to_string(), &c)]
+                .into_iter()
+                .collect();
+        assert!(validate_dependencies(&services, &["a".to_string()]).is_ok());
+    }
+}